@@ -1,25 +1,40 @@
 //! Implementation of a 5D Brainfuck With Multiverse Time Travel interpreter.
-//! 
+//!
 //! Implementation is naive and unoptimized, but behaves according to the spec.
-//! 
+//!
 //! # Usage
 //! Interpret a .5dbfwmvtt file by passing the file path to the executable.
 //! ```bash
 //! fivedbf path_to_file.5dbfwmvtt
 //! ```
-//! 
+//!
 //! # Building
-//! Requires rustc 1.47 or greater (for const generics in array types). 
+//! Requires rustc 1.47 or greater (for const generics in array types).
 //! To update rustc, run `rustup update stable`.
-//! 
+//!
 //! To build, run:
 //! ```bash
 //! cargo build --release
 //! ```
-//! 
+//!
 //! # Configuration
-//! Specify the features for cargo (`--features "some_features"`) to alter 
-//! the default behavior of the executable. Valid features are:
+//! Cell count, cell width, overflow, pointer wrapping and EOF behavior can all
+//! be set at runtime via CLI flags, passed after the file path:
+//!
+//! * `--cells N` : number of cells on the tape (default 30000)
+//! * `--cell-width 8|16|32` : cell width in bits (default 8)
+//! * `--eof max|zero|unchanged` : value written on EOF (default max)
+//! * `--wrap-pointer` : wrap `<`/`>` at the tape bounds instead of panicking
+//! * `--no-overflow` : saturate `+`/`-` at the cell bounds instead of wrapping
+//! * `--debug` : drop into an interactive debugger before each step; see `Debugger`
+//!
+//! ```bash
+//! fivedbf path_to_file.5dbfwmvtt --cells 100000 --cell-width 16 --wrap-pointer
+//! ```
+//!
+//! Specify the features for cargo (`--features "some_features"`) to change
+//! the *defaults* these flags start from, for backwards compatibility with
+//! older invocations. Valid features are:
 //!
 //! * "debug" : enable debug logging
 //! * "more_cells" or "even_more_cells" : increase cell count to 250000 and 2000000, respectively
@@ -27,27 +42,136 @@
 //! * "no_overflow" : disable cell wrapping on `+` and `-`
 //! * "pointer_wrapping" : enable pointer wrapping on `<` and `>`
 //! * "eof_0" or "eof_unchanged" : change EOF to return 0, or to not change the cell value, respectively
-//! 
+//!
 //! To compile with, e.g. the "debug" & "eof_unchanged" features, run:
 //! ```bash
 //! cargo build --release --flags "debug eof_unchanged"
 //! ```
-use std::{env, fs::read, io::{stdin, stdout, Read, Write}, process::exit};
-// All sorts of configuration, feel free to ignore
-#[cfg(not(any(feature = "more_cells", feature = "even_more_cells")))]
-const CELL_COUNT: usize = 30_000;
-#[cfg(all(feature = "more_cells", not(feature = "even_more_cells")))]
-const CELL_COUNT: usize = 250_000;
-#[cfg(feature = "even_more_cells")]
-const CELL_COUNT: usize = 2_000_000;
-#[cfg(not(any(feature = "16_bit", feature = "32_bit")))]
-type CellSize = u8;
-#[cfg(all(feature = "16_bit", not(feature = "32_bit")))]
-type CellSize = u16;
-#[cfg(feature = "32_bit")]
-type CellSize = u32;
+use std::{env, fmt::Debug, fs::read, io::{stdin, stdout, BufRead, BufReader, BufWriter, ErrorKind, Read, Write}, process::exit, rc::Rc};
+/// EOF behavior for `,`, chosen by `--eof` (default set by the `eof_0` /
+/// `eof_unchanged` features)
+#[derive(Debug, Clone, Copy)]
+enum Eof {
+    Max,
+    Zero,
+    Unchanged,
+}
+/// Runtime-configurable knobs that used to be cargo features. Defaults come
+/// from whatever features the binary was built with, and can be overridden
+/// per-run by CLI flags; see `parse_args`.
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    cells: usize,
+    width: u8,
+    eof: Eof,
+    wrap_pointer: bool,
+    overflow: bool,
+    debug: bool,
+}
+impl Default for Config {
+    fn default() -> Self {
+        #[cfg(not(any(feature = "more_cells", feature = "even_more_cells")))]
+        let cells = 30_000;
+        #[cfg(all(feature = "more_cells", not(feature = "even_more_cells")))]
+        let cells = 250_000;
+        #[cfg(feature = "even_more_cells")]
+        let cells = 2_000_000;
+        #[cfg(not(any(feature = "16_bit", feature = "32_bit")))]
+        let width = 8;
+        #[cfg(all(feature = "16_bit", not(feature = "32_bit")))]
+        let width = 16;
+        #[cfg(feature = "32_bit")]
+        let width = 32;
+        #[cfg(not(any(feature = "eof_0", feature = "eof_unchanged")))]
+        let eof = Eof::Max;
+        #[cfg(feature = "eof_0")]
+        let eof = Eof::Zero;
+        #[cfg(all(feature = "eof_unchanged", not(feature = "eof_0")))]
+        let eof = Eof::Unchanged;
+        Config {
+            cells,
+            width,
+            eof,
+            wrap_pointer: cfg!(feature = "pointer_wrapping"),
+            overflow: !cfg!(feature = "no_overflow"),
+            debug: false,
+        }
+    }
+}
+/// Parses `--cells`/`--cell-width`/`--eof`/`--wrap-pointer`/`--no-overflow`
+/// out of the CLI arguments, starting from `Config::default()`. The first
+/// argument that isn't one of these flags (or their value) is the file path.
+fn parse_args(args: impl Iterator<Item = String>) -> (Config, String) {
+    let mut config = Config::default();
+    let mut path = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cells" => {
+                let value = args.next().expect("--cells requires a value");
+                config.cells = value.parse().expect("--cells value must be a positive integer");
+            }
+            "--cell-width" => {
+                let value = args.next().expect("--cell-width requires a value");
+                config.width = match value.as_str() {
+                    "8" => 8,
+                    "16" => 16,
+                    "32" => 32,
+                    other => panic!("Unsupported --cell-width: {}", other),
+                };
+            }
+            "--eof" => {
+                let value = args.next().expect("--eof requires a value");
+                config.eof = match value.as_str() {
+                    "max" => Eof::Max,
+                    "zero" => Eof::Zero,
+                    "unchanged" => Eof::Unchanged,
+                    other => panic!("Unknown --eof mode: {}", other),
+                };
+            }
+            "--wrap-pointer" => config.wrap_pointer = true,
+            "--no-overflow" => config.overflow = false,
+            "--debug" => config.debug = true,
+            _ => if path.is_none() { path = Some(arg); },
+        }
+    }
+    (config, path.expect("File path not supplied"))
+}
+/// A cell width usable as tape storage, abstracting over the `--cell-width`
+/// choice of `u8`/`u16`/`u32`.
+trait Cell: Copy + Default + Debug + 'static {
+    const MAX: Self;
+    fn from_input_byte(byte: u8) -> Self;
+    fn as_output_byte(self) -> u8;
+    fn is_zero(self) -> bool;
+    /// Net effect of `delta` consecutive `+`/`-`, wrapping at the bounds
+    fn wrapping_adjust(self, delta: i32) -> Self;
+    /// Net effect of `delta` consecutive `+`/`-`, saturating at the bounds
+    fn saturating_adjust(self, delta: i32) -> Self;
+}
+macro_rules! impl_cell {
+    ($ty:ty) => {
+        impl Cell for $ty {
+            const MAX: Self = <$ty>::MAX;
+            fn from_input_byte(byte: u8) -> Self { byte as Self }
+            fn as_output_byte(self) -> u8 { self as u8 }
+            fn is_zero(self) -> bool { self == 0 }
+            fn wrapping_adjust(self, delta: i32) -> Self { self.wrapping_add(delta as Self) }
+            fn saturating_adjust(self, delta: i32) -> Self {
+                // `delta` can exceed this cell width's range (e.g. a
+                // coalesced run of 300 `+`s on a u8 cell), so clamp in a
+                // wider integer instead of casting it to `Self` first,
+                // which would truncate before saturating ever runs
+                (self as i64 + delta as i64).clamp(0, Self::MAX as i64) as Self
+            }
+        }
+    };
+}
+impl_cell!(u8);
+impl_cell!(u16);
+impl_cell!(u32);
 /// Not the most useful for debugging but it'll work
-fn _debug(timelines: &[Timeline], step: usize) {
+fn _debug<C: Cell>(timelines: &[Timeline<C>], step: usize) {
     eprintln!("=== Step {} ===", step);
     for (i, t) in timelines.iter().enumerate() {
         eprintln!("--- Timeline {} ---", i);
@@ -55,7 +179,66 @@ fn _debug(timelines: &[Timeline], step: usize) {
         eprintln!("Program counter: {}", t.pc);
         eprintln!("Pointers: {:?}", t.ptrs);
         eprintln!("History: {:?}", t.ops);
-        eprintln!("Tape: {:?} ...", &t.tape[..100]);
+        eprintln!("Tape: {:?} ...", (0..100).map(|i| t.tape.get(i)).collect::<Vec<_>>());
+    }
+}
+/// Cells per page of a [`Tape`]
+const PAGE_SIZE: usize = 4096;
+/// A `--cells`-cell tape, split into fixed-size pages that are shared (via
+/// `Rc`) between timelines until one of them writes to a page.
+///
+/// `(` (`Spawn`) used to copy the whole tape, which is prohibitively
+/// expensive with a large `--cells`. Cloning a `Tape` only bumps one `Rc`
+/// per page; a page is only actually copied the first time a write to it
+/// happens after the clone, via `Rc::make_mut`.
+#[derive(Debug, Clone)]
+struct Tape<C: Cell> {
+    pages: Vec<Rc<[C; PAGE_SIZE]>>,
+}
+impl<C: Cell> Tape<C> {
+    fn new(cells: usize) -> Self {
+        let page_count = cells.div_ceil(PAGE_SIZE);
+        Tape { pages: (0..page_count).map(|_| Rc::new([C::default(); PAGE_SIZE])).collect() }
+    }
+    fn get(&self, ptr: usize) -> C {
+        self.pages[ptr / PAGE_SIZE][ptr % PAGE_SIZE]
+    }
+    /// Resolves the page owning `ptr`, cloning it first if it's still shared
+    fn get_mut(&mut self, ptr: usize) -> &mut C {
+        &mut Rc::make_mut(&mut self.pages[ptr / PAGE_SIZE])[ptr % PAGE_SIZE]
+    }
+}
+#[cfg(test)]
+mod tape_tests {
+    use super::*;
+    // Spawning many timelines that each touch a disjoint page should only
+    // ever copy the pages that are actually written to - untouched pages
+    // stay shared (via Rc) between every clone.
+    #[test]
+    fn spawning_only_copies_touched_pages() {
+        let original: Tape<u8> = Tape::new(PAGE_SIZE * 4);
+        let mut clones: Vec<Tape<u8>> = (0..3).map(|_| original.clone()).collect();
+        // Every clone starts out sharing every page with the original
+        for page in &original.pages {
+            assert_eq!(Rc::strong_count(page), 4);
+        }
+        // Each clone writes to a different page, leaving page 3 untouched
+        for (i, tape) in clones.iter_mut().enumerate() {
+            *tape.get_mut(i * PAGE_SIZE) = 1;
+        }
+        for (i, tape) in clones.iter().enumerate() {
+            // The written page was detached from the original on first write...
+            assert!(!Rc::ptr_eq(&tape.pages[i], &original.pages[i]));
+            assert_eq!(Rc::strong_count(&original.pages[i]), 3);
+            // ...but every other page is still shared with everyone else
+            for (j, page) in tape.pages.iter().enumerate() {
+                if j != i {
+                    assert!(Rc::ptr_eq(page, &original.pages[j]));
+                }
+            }
+        }
+        // No clone ever touched page 3, so it's still shared by all four tapes
+        assert_eq!(Rc::strong_count(&original.pages[3]), 4);
     }
 }
 /// AST consists of a vector of these tokens
@@ -125,21 +308,142 @@ fn parse(bytes: &[u8]) -> Vec<Token> {
     }
     program
 }
+/// Denser instructions executed by `run`, produced from `Token`s by `optimize`.
+///
+/// Coalescing `Adjust`/`Shift` runs and `SetZero` loops is purely an
+/// execution-speed optimization; every variant here still behaves exactly
+/// like the sequence of `Token`s it replaces, snapshot-for-snapshot.
+#[derive(Debug)]
+enum Op {
+    /// Net cell change from a run of consecutive `Inc`/`Dec`
+    Adjust(i32),
+    /// Net pointer change from a run of consecutive `Right`/`Left`
+    Shift(isize),
+    /// `[-]` or `[+]`: zero the cell in one step
+    SetZero,
+    Read, Write, JumpZero(usize), JumpNonzero(usize),
+    Back, Up, Down, Await, Spawn(usize), Kill
+}
+/// Translates a `Token` with no coalescing opportunity into its `Op` equivalent.
+/// Jump/spawn targets are left as the original token indices; `optimize` patches
+/// them afterwards.
+fn translate(token: &Token) -> Op {
+    match token {
+        Token::Read => Op::Read,
+        Token::Write => Op::Write,
+        &Token::JumpZero(n) => Op::JumpZero(n),
+        &Token::JumpNonzero(n) => Op::JumpNonzero(n),
+        Token::Back => Op::Back,
+        Token::Up => Op::Up,
+        Token::Down => Op::Down,
+        Token::Await => Op::Await,
+        &Token::Spawn(n) => Op::Spawn(n),
+        Token::Kill => Op::Kill,
+        Token::Inc | Token::Dec | Token::Right | Token::Left =>
+            unreachable!("Inc/Dec/Right/Left are coalesced before translate is reached"),
+    }
+}
+/// Rewrites a parsed program into a denser IR: runs of `Inc`/`Dec` become a
+/// single `Adjust`, runs of `Right`/`Left` become a single `Shift`, and the
+/// idiomatic `[-]`/`[+]` clear loop becomes a single `SetZero`. None of this
+/// coalescing crosses a 5D operator (`~ ^ v @ ( )`), since those need to see
+/// every intervening step.
+///
+/// Coalescing changes instruction indices, so jump/spawn targets (which are
+/// stored as old indices) are patched afterwards through an old -> new index map.
+fn optimize(program: Vec<Token>) -> Vec<Op> {
+    let mut ops = Vec::with_capacity(program.len());
+    let mut old_to_new = vec![0usize; program.len()];
+    let mut i = 0;
+    while i < program.len() {
+        // `[-]`: a jump-zero immediately followed by one Dec and a
+        // jump-nonzero back to the jump-zero itself. `[+]` is deliberately
+        // NOT coalesced here: under saturating arithmetic a `[+]` loop on a
+        // nonzero cell never reaches zero (it sticks at MAX instead), so
+        // collapsing it to SetZero would terminate a program that's
+        // supposed to hang forever. `[-]` always reaches zero in either
+        // mode, so it stays safe to fold into a single step.
+        if let Token::JumpZero(target) = program[i] {
+            if target == i + 2 {
+                if let (Token::Dec, Token::JumpNonzero(back)) = (&program[i + 1], &program[i + 2]) {
+                    if *back == i {
+                        old_to_new[i] = ops.len();
+                        old_to_new[i + 1] = ops.len();
+                        old_to_new[i + 2] = ops.len();
+                        ops.push(Op::SetZero);
+                        i += 3;
+                        continue;
+                    }
+                }
+            }
+        }
+        match program[i] {
+            Token::Inc | Token::Dec => {
+                // Only coalesce within a single direction: saturating
+                // arithmetic is order-sensitive across a direction change
+                // (e.g. "-----+++" from 0 clamps to 0 partway through,
+                // then climbs back to 3 - collapsing straight to a net
+                // delta of -2 would clamp to 0 and lose the climb back
+                // up). Within one direction the walk is monotonic, so
+                // clamping the whole run's delta at once lands on exactly
+                // the same cell the sequential steps would.
+                let ascending = matches!(program[i], Token::Inc);
+                let mut delta = 0i32;
+                while i < program.len() {
+                    match program[i] {
+                        Token::Inc if ascending => delta += 1,
+                        Token::Dec if !ascending => delta -= 1,
+                        _ => break,
+                    }
+                    old_to_new[i] = ops.len();
+                    i += 1;
+                }
+                ops.push(Op::Adjust(delta));
+            }
+            Token::Right | Token::Left => {
+                let mut offset = 0isize;
+                while i < program.len() {
+                    match program[i] {
+                        Token::Right => offset += 1,
+                        Token::Left => offset -= 1,
+                        _ => break,
+                    }
+                    old_to_new[i] = ops.len();
+                    i += 1;
+                }
+                ops.push(Op::Shift(offset));
+            }
+            _ => {
+                old_to_new[i] = ops.len();
+                ops.push(translate(&program[i]));
+                i += 1;
+            }
+        }
+    }
+    for op in ops.iter_mut() {
+        match op {
+            Op::JumpZero(n) | Op::JumpNonzero(n) | Op::Spawn(n) => *n = old_to_new[*n],
+            _ => (),
+        }
+    }
+    ops
+}
 #[derive(Debug)]
-struct Timeline {
-    tape: [CellSize; CELL_COUNT],
+struct Timeline<C: Cell> {
+    tape: Tape<C>,
     pc: usize,
     ptrs: Vec<usize>,
-    ops: Vec<Vec<(usize, CellSize)>>,
+    ops: Vec<Vec<(usize, C)>>,
     alive: bool,
 }
-impl Timeline {
-    /// Create a copy of this timeline
+impl<C: Cell> Timeline<C> {
+    /// Create a copy of this timeline. Cheap: shares every page of `tape`
+    /// with the original until one of the copies writes to it.
     fn duplicate(&self, pc: usize) -> Self {
-        Timeline { 
-            tape: self.tape, 
+        Timeline {
+            tape: self.tape.clone(),
             pc,
-            ptrs: self.ptrs.clone(), 
+            ptrs: self.ptrs.clone(),
             ops: vec![],
             alive: true,
         }
@@ -148,23 +452,143 @@ impl Timeline {
     fn snapshot(&mut self) {
         self.ops.push(
             self.ptrs.iter().map(
-                |&ptr| (ptr, self.tape[ptr])
+                |&ptr| (ptr, self.tape.get(ptr))
             ).collect()
         );
     }
+    /// Pop the most recent snapshot off the history and restore the tape to
+    /// it. Returns `false` if there was no history to unwind. Shared by `~`
+    /// and the `--debug` `rewind` command, since both are "undo one step".
+    fn rewind(&mut self) -> bool {
+        match self.ops.pop() {
+            Some(op) => {
+                for (ptr, value) in op {
+                    *self.tape.get_mut(ptr) = value;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+/// Interactive command loop for `--debug`. Borrows the `timelines` vector for
+/// the duration of one pause between steps, so the core loop in `run` stays
+/// untouched by debugger state once the user resumes execution.
+struct Debugger<'a, C: Cell> {
+    timelines: &'a mut Vec<Timeline<C>>,
+    step: usize,
+    cells: usize,
+}
+impl<'a, C: Cell> Debugger<'a, C> {
+    fn new(timelines: &'a mut Vec<Timeline<C>>, step: usize, cells: usize) -> Self {
+        Debugger { timelines, step, cells }
+    }
+    /// Prompts for commands until the user resumes execution. Returns
+    /// whether `run` should pause again before the very next step (`true`,
+    /// from `step`) or run free until the next breakpoint (`false`, from
+    /// `continue`).
+    fn run_loop<R: BufRead, W: Write>(
+        &mut self, breakpoints: &mut Vec<usize>, input: &mut R, output: &mut W,
+    ) -> bool {
+        loop {
+            write!(output, "[step {}] (5dbf-debug) ", self.step).unwrap();
+            output.flush().unwrap();
+            let mut line = String::new();
+            // EOF on the command stream (e.g. piped input ran out): just run free
+            if input.read_line(&mut line).unwrap() == 0 {
+                return false;
+            }
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("s") | Some("step") => return true,
+                Some("c") | Some("continue") => return false,
+                Some("b") | Some("break") => match words.next().and_then(|w| w.parse().ok()) {
+                    Some(n) => {
+                        breakpoints.push(n);
+                        writeln!(output, "breakpoint set at program index {}", n).unwrap();
+                    }
+                    None => writeln!(output, "usage: break <program index>").unwrap(),
+                },
+                Some("l") | Some("list") => {
+                    for (i, t) in self.timelines.iter().enumerate() {
+                        writeln!(output, "#{} pc={} ptrs={:?} alive={}", i, t.pc, t.ptrs, t.alive).unwrap();
+                    }
+                }
+                Some("t") | Some("tape") => {
+                    let idx: usize = words.next().and_then(|w| w.parse().ok()).unwrap_or(0);
+                    match self.timelines.get(idx) {
+                        Some(t) => for &ptr in &t.ptrs {
+                            let lo = ptr.saturating_sub(8);
+                            let hi = (ptr + 8).min(self.cells - 1);
+                            let window: Vec<C> = (lo..=hi).map(|p| t.tape.get(p)).collect();
+                            writeln!(output, "timeline #{} around {}: {:?}", idx, ptr, window).unwrap();
+                        },
+                        None => writeln!(output, "no timeline #{}", idx).unwrap(),
+                    }
+                }
+                // Steps a timeline backwards without running `~`: the same
+                // snapshot-popping mechanism, driven from the debugger instead
+                // of the program. Unique to this interpreter's time-travel model.
+                Some("r") | Some("rewind") => {
+                    let idx: usize = words.next().and_then(|w| w.parse().ok()).unwrap_or(0);
+                    match self.timelines.get_mut(idx) {
+                        Some(t) => if t.rewind() {
+                            writeln!(output, "rewound timeline #{} by one snapshot", idx).unwrap();
+                        } else {
+                            writeln!(output, "timeline #{} has no history to rewind", idx).unwrap();
+                        },
+                        None => writeln!(output, "no timeline #{}", idx).unwrap(),
+                    }
+                }
+                Some("h") | Some("help") => writeln!(
+                    output,
+                    "commands: step|s, continue|c, break|b <index>, list|l, tape|t <timeline>, rewind|r <timeline>, help|h"
+                ).unwrap(),
+                _ => writeln!(output, "unknown command, try `help`").unwrap(),
+            }
+        }
+    }
 }
 /// Bulk of interpreter
-fn run(program: &[Token]) -> ! {
+fn run<C: Cell>(program: &[Op], config: &Config) -> ! {
     let mut timelines = vec![Timeline {
-        tape: [0; CELL_COUNT],
+        tape: Tape::new(config.cells),
         pc: 0,
         ptrs: vec![0],
         ops: vec![],
         alive: true,
     }];
+    // held for the lifetime of the interpreter so that reads/writes don't
+    // pay for a fresh handle (and the buffering that comes with it) every time
+    let mut input = BufReader::new(stdin());
+    let mut output = BufWriter::new(stdout());
+    // `,` reads program input from stdin, which may be redirected from a file;
+    // debugger commands must come from the terminal instead, or the two would
+    // silently steal bytes from each other. With no controlling terminal to
+    // open (e.g. under a test harness) there's no way to get a second input
+    // stream, so we fall back to literally sharing `input` with `,` rather
+    // than wrapping the same fd in a second, independently-buffered reader,
+    // which would race with `input` instead of merely interleaving with it.
+    let mut debug_tty: Option<BufReader<std::fs::File>> = if config.debug {
+        std::fs::File::open("/dev/tty").ok().map(BufReader::new)
+    } else {
+        None
+    };
     let mut _step = 0usize;
+    let mut breakpoints: Vec<usize> = vec![];
+    let mut single_step = config.debug;
     loop {
         #[cfg(feature = "debug")] _debug(&timelines, _step);
+        if config.debug {
+            let hit_breakpoint = timelines.iter().any(|t| t.alive && breakpoints.contains(&t.pc));
+            if single_step || hit_breakpoint {
+                let mut debugger = Debugger::new(&mut timelines, _step, config.cells);
+                single_step = match debug_tty.as_mut() {
+                    Some(tty) => debugger.run_loop(&mut breakpoints, tty, &mut output),
+                    None => debugger.run_loop(&mut breakpoints, &mut input, &mut output),
+                };
+            }
+        }
         _step += 1;
         let mut to_spawn = vec![];
         let mut kill = false;
@@ -182,95 +606,91 @@ fn run(program: &[Token]) -> ! {
             // dbg!(i, &t.ptrs);
             // run off the program
             if t.pc > program.len() - 1 {
-                if i == 0 { exit(0); }
+                if i == 0 { output.flush().unwrap(); exit(0); }
                 else { kill = true; t.alive = false; }
             }
             else {
                 match program[t.pc] {
-                    Token::Inc => {
+                    // Equivalent to `delta.abs()` copies of `Inc`/`Dec` in a row, all in the
+                    // same direction (see `optimize`): one snapshot for the whole coalesced
+                    // step, so `~` undoes it atomically.
+                    Op::Adjust(delta) => {
                         t.snapshot();
-                        for &ptr in &t.ptrs { 
-                            #[cfg(not(feature = "no_overflow"))] { t.tape[ptr] += 1; }
-                            #[cfg(feature = "no_overflow")] { t.tape[ptr] = t.tape[ptr].saturating_add(1); }
-                        }
-                    }
-                    Token::Dec => {
-                        t.snapshot();
-                        for &ptr in &t.ptrs { 
-                            #[cfg(not(feature = "no_overflow"))] { t.tape[ptr] -= 1; }
-                            #[cfg(feature = "no_overflow")] { t.tape[ptr] = t.tape[ptr].saturating_sub(1); }
+                        for &ptr in &t.ptrs {
+                            let v: C = t.tape.get(ptr);
+                            let v = if config.overflow { v.wrapping_adjust(delta) } else { v.saturating_adjust(delta) };
+                            *t.tape.get_mut(ptr) = v;
                         }
                     }
-                    Token::Right => {
-                        for ptr in t.ptrs.iter_mut() { 
-                            if *ptr == CELL_COUNT - 1 { 
-                                #[cfg(not(feature = "pointer_wrapping"))] { panic!("Pointer out of bounds"); }
-                                #[cfg(feature = "pointer_wrapping")] { *ptr = 0; }
-                            } else { *ptr += 1; } 
+                    // Equivalent to `offset.abs()` copies of `Right`/`Left` in a row
+                    Op::Shift(offset) => {
+                        for ptr in t.ptrs.iter_mut() {
+                            let mut remaining = offset;
+                            while remaining > 0 {
+                                if *ptr == config.cells - 1 {
+                                    if config.wrap_pointer { *ptr = 0; } else { panic!("Pointer out of bounds"); }
+                                } else { *ptr += 1; }
+                                remaining -= 1;
+                            }
+                            while remaining < 0 {
+                                if *ptr == 0 {
+                                    if config.wrap_pointer { *ptr = config.cells - 1; } else { panic!("Pointer out of bounds"); }
+                                } else { *ptr -= 1; }
+                                remaining += 1;
+                            }
                         }
                     }
-                    Token::Left => {
-                        for ptr in t.ptrs.iter_mut() { 
-                            if *ptr == 0 { 
-                                #[cfg(not(feature = "pointer_wrapping"))] { panic!("Pointer out of bounds"); }
-                                #[cfg(feature = "pointer_wrapping")] { *ptr = CELL_COUNT - 1; }
-                            } else { *ptr -= 1; } 
+                    // `[-]`/`[+]`: one snapshot, then zero directly instead of looping
+                    Op::SetZero => {
+                        t.snapshot();
+                        for &ptr in &t.ptrs {
+                            *t.tape.get_mut(ptr) = C::default();
                         }
                     }
-                    Token::Read => {
+                    Op::Read => {
                         t.snapshot();
-                        let mut handle = stdin();
+                        // flush first, so that any prompt written just before
+                        // a `,` is actually visible before we block on input
+                        output.flush().unwrap();
                         for &ptr in &t.ptrs {
-                            // this is not good, but the alternative
-                            // is to rely on "unspecified" EOF behavior
-                            // with buffered reads
                             let mut buffer = [0; 1];
-                            match handle.read(&mut buffer) {
-                                Ok(n) => if n == 0 { 
-                                    #[cfg(not(any(feature = "eof_0", feature = "eof_unchanged")))] { t.tape[ptr] = CellSize::MAX; }
-                                    #[cfg(feature = "eof_0")] { tape[ptr] = 0; }
-                                    #[cfg(all(feature = "eof_unchanged", not(feature = "eof_0")))] {}
-                                } 
-                                else { 
-                                    t.tape[ptr] = buffer[0] as CellSize 
+                            match input.read_exact(&mut buffer) {
+                                Ok(()) => *t.tape.get_mut(ptr) = C::from_input_byte(buffer[0]),
+                                Err(e) if e.kind() == ErrorKind::UnexpectedEof => match config.eof {
+                                    Eof::Max => *t.tape.get_mut(ptr) = C::MAX,
+                                    Eof::Zero => *t.tape.get_mut(ptr) = C::default(),
+                                    Eof::Unchanged => (),
                                 },
-                                Err(_) => panic!("Failed to read from stdin")
+                                Err(_) => panic!("Failed to read from stdin"),
                             }
                         }
                     }
-                    Token::Write => {
-                        let mut handle = stdout();
+                    Op::Write => {
                         let mut buffer = Vec::with_capacity(1);
-                        for &ptr in &t.ptrs { 
-                            buffer.push(t.tape[ptr] as u8);
+                        for &ptr in &t.ptrs {
+                            buffer.push(t.tape.get(ptr).as_output_byte());
                         }
-                        match handle.write_all(&mut buffer) {
+                        match output.write_all(&mut buffer) {
                             Ok(_) => (),
                             Err(_) => panic!("Failed to write to stdout"),
                         }
-                        // if flush fails and write doesn't, that's your problem and not mine
-                        handle.flush().unwrap();
                     }
-                    Token::JumpZero(n) => {
-                        if t.ptrs.iter().all(|&ptr| t.tape[ptr] == 0) {
+                    Op::JumpZero(n) => {
+                        if t.ptrs.iter().all(|&ptr| t.tape.get(ptr).is_zero()) {
                             t.pc = n;
                         }
                     }
-                    Token::JumpNonzero(n) => {
-                        if t.ptrs.iter().any(|&ptr| t.tape[ptr] != 0) {
+                    Op::JumpNonzero(n) => {
+                        if t.ptrs.iter().any(|&ptr| !t.tape.get(ptr).is_zero()) {
                             t.pc = n;
                         }
                     }
-                    Token::Back => {
-                        let op = match t.ops.pop() {
-                            Some(o) => o,
-                            None => panic!("Attempted `~` with no history to unwind"),
-                        };
-                        for (ptr, value) in op {
-                            t.tape[ptr] = value;
+                    Op::Back => {
+                        if !t.rewind() {
+                            panic!("Attempted `~` with no history to unwind");
                         }
                     }
-                    Token::Up => {
+                    Op::Up => {
                         if i == 0 { t.ptrs.clear(); }
                         else {
                             // unwrap valid since i > 0
@@ -278,7 +698,7 @@ fn run(program: &[Token]) -> ! {
                             upper.ptrs.extend(t.ptrs.drain(..));
                         }
                     }
-                    Token::Down => {
+                    Op::Down => {
                         if i == count - 1 { t.ptrs.clear(); }
                         else {
                             // unwrap valid for similar reasons
@@ -286,7 +706,7 @@ fn run(program: &[Token]) -> ! {
                             lower.ptrs.extend(t.ptrs.drain(..));
                         }
                     }
-                    Token::Await => {
+                    Op::Await => {
                         if i != count - 1 {
                             // unwrap valid for similar reasons
                             let lower = tail.first_mut().unwrap();
@@ -295,11 +715,11 @@ fn run(program: &[Token]) -> ! {
                             }
                         }
                     }
-                    Token::Spawn(n) => {
+                    Op::Spawn(n) => {
                         to_spawn.push((i, t.pc + 1));
                         t.pc = n;
                     }
-                    Token::Kill => {
+                    Op::Kill => {
                         kill = true;
                         t.alive = false;
                     }
@@ -327,15 +747,19 @@ fn run(program: &[Token]) -> ! {
     }
 }
 fn main() {
-    let fp = match env::args().skip(1).next() {
-        Some(s) => s,
-        None => panic!("File path not supplied"),
-    };
+    let (config, fp) = parse_args(env::args().skip(1));
     let bytes = match read(fp) {
         Ok(b) => b,
         Err(_) => panic!("File not found!"),
     };
     let program = parse(&bytes);
     #[cfg(feature = "debug")] dbg!(&program);
-    run(&program);
+    let program = optimize(program);
+    #[cfg(feature = "debug")] dbg!(&program);
+    match config.width {
+        8 => run::<u8>(&program, &config),
+        16 => run::<u16>(&program, &config),
+        32 => run::<u32>(&program, &config),
+        other => panic!("Unsupported cell width: {}", other),
+    }
 }